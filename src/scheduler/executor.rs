@@ -0,0 +1,178 @@
+// Copyright (c) 2017 Stefan Lankes, RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+#![allow(dead_code)]
+
+//! A cooperative executor for `no_std` futures, living alongside the
+//! preemptive, stack-switched `Task`s from `scheduler::task`.
+//!
+//! Instead of owning a stack, a future-task only owns a
+//! `Pin<Box<dyn Future<Output = ()>>>` and cooperates by returning
+//! `Poll::Pending` whenever it has nothing left to do. This is a much
+//! lighter-weight path for I/O-style waiting than blocking a whole TCB.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use logging::*;
+
+/// Maximum number of future-tasks that may be ready to run at once.
+const MAX_READY_TASKS: usize = 128;
+
+/// Identity of a future-task, distinct from `scheduler::task::TaskId` so a
+/// `FutureTask` can never be confused with a real, stack-switched `Task`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+struct FutureId(u32);
+
+impl fmt::Display for FutureId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+fn next_id() -> FutureId {
+	FutureId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A future-task managed by the `Executor`.
+struct FutureTask {
+	id: FutureId,
+	future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl FutureTask {
+	fn new(future: impl Future<Output = ()> + 'static) -> FutureTask {
+		FutureTask {
+			id: next_id(),
+			future: Box::pin(future),
+		}
+	}
+
+	fn poll(&mut self, context: &mut Context) -> Poll<()> {
+		self.future.as_mut().poll(context)
+	}
+}
+
+/// Wakes a future-task by pushing its `FutureId` back onto the executor's
+/// ready queue. Cloning is cheap (an `Arc` bump) so a waker can be handed
+/// out to interrupt handlers and fired from interrupt context.
+struct TaskWaker {
+	task_id: FutureId,
+	ready_queue: Arc<ArrayQueue<FutureId>>,
+}
+
+impl TaskWaker {
+	fn waker(task_id: FutureId, ready_queue: Arc<ArrayQueue<FutureId>>) -> Waker {
+		Waker::from(Arc::new(TaskWaker {
+			task_id,
+			ready_queue,
+		}))
+	}
+
+	fn wake_task(&self) {
+		if self.ready_queue.push(self.task_id).is_err() {
+			warn!("Ready queue full, dropping wakeup for task {}", self.task_id);
+		}
+	}
+}
+
+impl Wake for TaskWaker {
+	fn wake(self: Arc<Self>) {
+		self.wake_task();
+	}
+
+	fn wake_by_ref(self: &Arc<Self>) {
+		self.wake_task();
+	}
+}
+
+/// Cooperative executor for `no_std` futures.
+///
+/// Futures are kept in a slab keyed by `FutureId` so a `Waker` fired from
+/// interrupt context only needs the id to find its way back onto the
+/// lock-free ready queue.
+pub struct Executor {
+	tasks: BTreeMap<FutureId, FutureTask>,
+	ready_queue: Arc<ArrayQueue<FutureId>>,
+	wakers: BTreeMap<FutureId, Waker>,
+}
+
+impl Executor {
+	pub fn new() -> Executor {
+		Executor {
+			tasks: BTreeMap::new(),
+			ready_queue: Arc::new(ArrayQueue::new(MAX_READY_TASKS)),
+			wakers: BTreeMap::new(),
+		}
+	}
+
+	/// Register a future as a new, immediately-ready task.
+	///
+	/// Returns `None` (and drops the future) if the ready queue is already
+	/// full, the same degrade-not-die behavior `TaskWaker::wake_task` uses
+	/// for a full queue, rather than taking the kernel down over backpressure.
+	pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) -> Option<FutureId> {
+		let task = FutureTask::new(future);
+		let id = task.id;
+
+		if self.ready_queue.push(id).is_err() {
+			warn!("Ready queue full, dropping spawn of task {}", id);
+			return None;
+		}
+		if self.tasks.insert(id, task).is_some() {
+			panic!("Task with id {} already exists", id);
+		}
+
+		Some(id)
+	}
+
+	/// Drain the ready queue, polling the future of every id popped from it.
+	/// A future woken during its own poll is polled again before this returns.
+	///
+	/// A future that returns `Poll::Ready` is dropped together with its
+	/// waker; a future that returns `Poll::Pending` stays parked until its
+	/// waker pushes its id back onto the ready queue.
+	pub fn run_ready_tasks(&mut self) {
+		while let Ok(id) = self.ready_queue.pop() {
+			let task = match self.tasks.get_mut(&id) {
+				Some(task) => task,
+				// The task already finished and raced with a stale wakeup.
+				None => continue,
+			};
+
+			let ready_queue = self.ready_queue.clone();
+			let waker = self
+				.wakers
+				.entry(id)
+				.or_insert_with(|| TaskWaker::waker(id, ready_queue))
+				.clone();
+			let mut context = Context::from_waker(&waker);
+
+			match task.poll(&mut context) {
+				Poll::Ready(()) => {
+					self.tasks.remove(&id);
+					self.wakers.remove(&id);
+				}
+				Poll::Pending => {}
+			}
+		}
+	}
+
+	#[inline(always)]
+	pub fn is_empty(&self) -> bool {
+		self.tasks.is_empty()
+	}
+}
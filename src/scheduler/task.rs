@@ -9,11 +9,12 @@
 
 use alloc;
 use alloc::alloc::{alloc, dealloc, Layout};
-use alloc::collections::LinkedList;
+use alloc::collections::{BTreeMap, LinkedList};
 use alloc::rc::Rc;
 use consts::*;
 use core::cell::RefCell;
 use core::fmt;
+use core::mem::{self, MaybeUninit};
 use logging::*;
 
 extern "C" {
@@ -83,19 +84,54 @@ pub struct Stack {
 	buffer: [u8; STACK_SIZE],
 }
 
+/// Size, in bytes, of the canary guard region at the bottom of each stack
+const GUARD_SIZE: usize = 16;
+
+/// Pattern written into the guard region to detect a stack overflow
+const GUARD_CANARY: u64 = 0xDEAD_C0DE_DEAD_C0DE;
+
 impl Stack {
 	pub const fn new() -> Stack {
-		Stack {
-			buffer: [0; STACK_SIZE],
+		let mut buffer = [0; STACK_SIZE];
+		let canary = GUARD_CANARY.to_ne_bytes();
+
+		// Arm the guard region at the bottom of the stack. `for` isn't
+		// allowed in a `const fn`, hence the manual `while`.
+		let mut i = 0;
+		while i < GUARD_SIZE {
+			buffer[i] = canary[i % 8];
+			i += 1;
 		}
+
+		Stack { buffer }
 	}
 
 	pub fn top(&self) -> usize {
 		(&(self.buffer[STACK_SIZE - 16]) as *const _) as usize
 	}
 
+	/// Address of the lowest byte of the usable region, above the guard
 	pub fn bottom(&self) -> usize {
-		(&(self.buffer[0]) as *const _) as usize
+		(&(self.buffer[GUARD_SIZE]) as *const _) as usize
+	}
+
+	/// Check whether the guard canary is still intact
+	pub fn check_integrity(&self) -> bool {
+		let canary = GUARD_CANARY.to_ne_bytes();
+
+		self.buffer[0..GUARD_SIZE]
+			.iter()
+			.enumerate()
+			.all(|(i, byte)| *byte == canary[i % 8])
+	}
+
+	/// Write the guard canary into an allocator-provided (uninitialized) stack
+	fn arm_guard(&mut self) {
+		let canary = GUARD_CANARY.to_ne_bytes();
+
+		for i in 0..GUARD_SIZE {
+			self.buffer[i] = canary[i % 8];
+		}
 	}
 }
 
@@ -150,6 +186,97 @@ impl Default for TaskQueue {
 		}
 	}
 }
+
+/// A ready queue with `NO_PRIORITIES` per-priority FIFOs plus a bitmap
+/// tracking which ones are non-empty, for O(1) highest-priority selection.
+pub struct PriorityTaskQueue {
+	queues: [LinkedList<Rc<RefCell<Task>>>; NO_PRIORITIES],
+	prio_bitmap: u64,
+}
+
+impl PriorityTaskQueue {
+	pub fn new() -> PriorityTaskQueue {
+		// `LinkedList` isn't `Copy`, so the array can't be built with a
+		// single repeat expression. Initialize each slot in place instead.
+		let queues: [LinkedList<Rc<RefCell<Task>>>; NO_PRIORITIES] = {
+			let mut queues: [MaybeUninit<LinkedList<Rc<RefCell<Task>>>>; NO_PRIORITIES] =
+				unsafe { MaybeUninit::uninit().assume_init() };
+
+			for q in queues.iter_mut() {
+				*q = MaybeUninit::new(LinkedList::new());
+			}
+
+			unsafe { mem::transmute_copy(&queues) }
+		};
+
+		PriorityTaskQueue {
+			queues: queues,
+			prio_bitmap: 0,
+		}
+	}
+
+	/// Add a task to the queue
+	pub fn push(&mut self, task: Rc<RefCell<Task>>) {
+		let prio = task.borrow().prio.into() as usize;
+		assert!(prio < NO_PRIORITIES);
+
+		self.queues[prio].push_back(task);
+		self.prio_bitmap |= 1 << prio;
+	}
+
+	/// Pop the task with the highest priority (i.e. the lowest priority
+	/// number) from the queue, preserving FIFO order within that priority.
+	pub fn pop(&mut self) -> Option<Rc<RefCell<Task>>> {
+		if self.prio_bitmap == 0 {
+			return None;
+		}
+
+		let prio = self.prio_bitmap.trailing_zeros() as usize;
+		let task = self.queues[prio].pop_front();
+
+		if self.queues[prio].is_empty() {
+			self.prio_bitmap &= !(1 << prio);
+		}
+
+		task
+	}
+
+	#[inline(always)]
+	pub fn is_empty(&self) -> bool {
+		self.prio_bitmap == 0
+	}
+
+	/// Remove a specific task from the priority queue.
+	pub fn remove(&mut self, task: Rc<RefCell<Task>>) {
+		let prio = task.borrow().prio.into() as usize;
+		assert!(prio < NO_PRIORITIES);
+
+		let mut cursor = self.queues[prio].cursor_front_mut();
+
+		// Loop through all blocked tasks to find it.
+		while let Some(node) = cursor.current() {
+			if Rc::ptr_eq(&node, &task) {
+				// Remove it from the list
+				cursor.remove_current();
+
+				if self.queues[prio].is_empty() {
+					self.prio_bitmap &= !(1 << prio);
+				}
+
+				break;
+			}
+
+			cursor.move_next();
+		}
+	}
+}
+
+impl Default for PriorityTaskQueue {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
 /// A task control block, which identifies either a process or a thread
 #[repr(align(64))]
 pub struct Task {
@@ -163,41 +290,104 @@ pub struct Task {
 	pub last_stack_pointer: usize,
 	// Stack of the task
 	pub stack: *mut Stack,
+	/// Exit code, set once this task has reached `TaskFinished`
+	pub exit_code: Option<i32>,
+	/// Tasks currently blocked in `join` on this one
+	pub waiters: LinkedList<TaskId>,
+	/// Number of `join` calls that have registered interest in this task's
+	/// exit code but not yet observed it; the TCB stays in the `tasks()`
+	/// registry until this drops back to zero.
+	pub pending_joiners: usize,
+	/// Task-local storage slots, keyed by an application-defined id
+	pub tls: BTreeMap<u32, usize>,
 }
 
 impl Task {
-	pub fn new_idle(id: TaskId) -> Task {
-		Task {
+	/// Create the idle task and register it, so `join`/`task_exit`/TLS can
+	/// find it the same way as any other task.
+	pub fn new_idle(id: TaskId) -> Rc<RefCell<Task>> {
+		let task = Rc::new(RefCell::new(Task {
 			id: id,
 			prio: LOW_PRIORITY,
 			status: TaskStatus::TaskIdle,
 			last_stack_pointer: 0,
 			stack: unsafe { &mut BOOT_STACK },
-		}
+			exit_code: None,
+			waiters: Default::default(),
+			pending_joiners: 0,
+			// `BOOT_STACK` is shared by every idle task, but each CPU gets
+			// its own `Task` instance, so keying TLS off the struct (not
+			// the stack) already gives every boot task its own slots.
+			tls: BTreeMap::new(),
+		}));
+
+		register_task(task.clone());
+		task
 	}
 
-	pub fn new(id: TaskId, status: TaskStatus, prio: TaskPriority) -> Task {
+	/// Allocate a stack for a new task and register it, so `join`/
+	/// `task_exit`/TLS can find it by id without relying on the caller to
+	/// remember to do so.
+	pub fn new(id: TaskId, status: TaskStatus, prio: TaskPriority) -> Rc<RefCell<Task>> {
 		let stack = unsafe { alloc(Layout::new::<Stack>()) as *mut Stack };
 
 		debug!("Allocate stack for task {} at 0x{:x}", id, stack as usize);
 
-		Task {
+		unsafe {
+			(*stack).arm_guard();
+		}
+
+		let task = Rc::new(RefCell::new(Task {
 			id: id,
 			prio: prio,
 			status: status,
 			last_stack_pointer: 0,
 			stack: stack,
-		}
+			exit_code: None,
+			waiters: Default::default(),
+			pending_joiners: 0,
+			tls: BTreeMap::new(),
+		}));
+
+		register_task(task.clone());
+		task
+	}
+
+	/// Record `code` as the exit status, flip to `TaskFinished`, and return
+	/// the ids of every task that was blocked in `join` on this one so the
+	/// caller can re-ready them.
+	pub fn exit(&mut self, code: i32) -> LinkedList<TaskId> {
+		self.exit_code = Some(code);
+		self.status = TaskStatus::TaskFinished;
+
+		mem::replace(&mut self.waiters, Default::default())
+	}
+}
+
+/// Check a task's stack guard, panicking with its `TaskId` if clobbered
+pub fn check_stack_integrity(task: &Rc<RefCell<Task>>) {
+	let task = task.borrow();
+
+	if !unsafe { &*task.stack }.check_integrity() {
+		panic!("Stack overflow detected in task {}", task.id);
 	}
 }
 
 pub trait TaskFrame {
-	/// Create the initial stack frame for a new task
+	/// Create the initial stack frame for a new task.
+	///
+	/// The frame must be built no lower than `stack.bottom()`, which sits
+	/// just above the guard canary reserved at the bottom of the stack.
 	fn create_stack_frame(&mut self, func: extern "C" fn());
 }
 
 impl Drop for Task {
 	fn drop(&mut self) {
+		// Drop the TLS slots explicitly, symmetrically with the stack
+		// deallocation below, rather than relying on the implicit `BTreeMap`
+		// drop glue.
+		self.tls.clear();
+
 		if unsafe { self.stack != &mut BOOT_STACK } {
 			debug!(
 				"Deallocate stack of task {} (stack at 0x{:x})",
@@ -211,3 +401,277 @@ impl Drop for Task {
 		}
 	}
 }
+
+/// The task currently executing, for task-local storage to read and write
+static mut CURRENT_TASK: Option<Rc<RefCell<Task>>> = None;
+
+/// Record `task` as the one currently executing
+pub fn set_current_task(task: Rc<RefCell<Task>>) {
+	unsafe {
+		CURRENT_TASK = Some(task);
+	}
+}
+
+/// The task currently executing, if any.
+pub fn current_task() -> Option<Rc<RefCell<Task>>> {
+	unsafe { CURRENT_TASK.clone() }
+}
+
+/// Store `val` under `key` in the currently running task's TLS slots.
+pub fn set_tls(key: u32, val: usize) {
+	if let Some(task) = current_task() {
+		task.borrow_mut().tls.insert(key, val);
+	}
+}
+
+/// Read `key` out of the currently running task's TLS slots.
+pub fn get_tls(key: u32) -> Option<usize> {
+	current_task().and_then(|task| task.borrow().tls.get(&key).cloned())
+}
+
+/// A pluggable task ordering discipline, selectable at boot
+pub trait Scheduler {
+	/// Add a newly created (or re-readied) task to the ready set.
+	fn add_task(&mut self, task: Rc<RefCell<Task>>);
+
+	/// Pick the next task to run and mark it as the current one.
+	fn next_task(&mut self) -> Option<Rc<RefCell<Task>>>;
+
+	/// The task that is currently marked as running, if any.
+	fn current_task(&self) -> Option<Rc<RefCell<Task>>>;
+
+	/// Remove a task from the ready set, e.g. because it blocked or exited.
+	fn remove_task(&mut self, task: Rc<RefCell<Task>>);
+
+	/// Change the priority of a ready task, re-bucketing it if necessary.
+	fn set_priority(&mut self, id: TaskId, prio: TaskPriority);
+}
+
+/// Simple round-robin scheduler built on the plain FIFO `TaskQueue`
+pub struct CoopScheduler {
+	ready_queue: TaskQueue,
+	current_task: Option<Rc<RefCell<Task>>>,
+}
+
+impl CoopScheduler {
+	pub fn new() -> CoopScheduler {
+		CoopScheduler {
+			ready_queue: TaskQueue::new(),
+			current_task: None,
+		}
+	}
+}
+
+impl Scheduler for CoopScheduler {
+	fn add_task(&mut self, task: Rc<RefCell<Task>>) {
+		task.borrow_mut().status = TaskStatus::TaskReady;
+		self.ready_queue.push(task);
+	}
+
+	fn next_task(&mut self) -> Option<Rc<RefCell<Task>>> {
+		if let Some(ref prev) = self.current_task {
+			check_stack_integrity(prev);
+		}
+
+		let task = self.ready_queue.pop();
+
+		if let Some(ref task) = task {
+			self.current_task = Some(task.clone());
+			set_current_task(task.clone());
+		}
+
+		task
+	}
+
+	fn current_task(&self) -> Option<Rc<RefCell<Task>>> {
+		self.current_task.clone()
+	}
+
+	fn remove_task(&mut self, task: Rc<RefCell<Task>>) {
+		self.ready_queue.remove(task);
+	}
+
+	fn set_priority(&mut self, _id: TaskId, _prio: TaskPriority) {
+		// A flat FIFO has no buckets to re-bucket into.
+	}
+}
+
+/// Priority scheduler built on `PriorityTaskQueue`, with a side table of
+/// ready tasks so `set_priority` can re-bucket one in place.
+pub struct PriorityScheduler {
+	ready_queue: PriorityTaskQueue,
+	ready_tasks: BTreeMap<TaskId, Rc<RefCell<Task>>>,
+	current_task: Option<Rc<RefCell<Task>>>,
+}
+
+impl PriorityScheduler {
+	pub fn new() -> PriorityScheduler {
+		PriorityScheduler {
+			ready_queue: PriorityTaskQueue::new(),
+			ready_tasks: BTreeMap::new(),
+			current_task: None,
+		}
+	}
+}
+
+impl Scheduler for PriorityScheduler {
+	fn add_task(&mut self, task: Rc<RefCell<Task>>) {
+		task.borrow_mut().status = TaskStatus::TaskReady;
+		self.ready_tasks.insert(task.borrow().id, task.clone());
+		self.ready_queue.push(task);
+	}
+
+	fn next_task(&mut self) -> Option<Rc<RefCell<Task>>> {
+		if let Some(ref prev) = self.current_task {
+			check_stack_integrity(prev);
+		}
+
+		let task = self.ready_queue.pop();
+
+		if let Some(ref task) = task {
+			self.ready_tasks.remove(&task.borrow().id);
+			self.current_task = Some(task.clone());
+			set_current_task(task.clone());
+		}
+
+		task
+	}
+
+	fn current_task(&self) -> Option<Rc<RefCell<Task>>> {
+		self.current_task.clone()
+	}
+
+	fn remove_task(&mut self, task: Rc<RefCell<Task>>) {
+		self.ready_tasks.remove(&task.borrow().id);
+		self.ready_queue.remove(task);
+	}
+
+	fn set_priority(&mut self, id: TaskId, prio: TaskPriority) {
+		if let Some(task) = self.ready_tasks.get(&id).cloned() {
+			// Pull the task out of its current priority bucket, update the
+			// TCB, and re-insert it so it lands in the right bucket.
+			self.ready_queue.remove(task.clone());
+			task.borrow_mut().prio = prio;
+			self.ready_queue.push(task);
+		} else if let Some(ref task) = self.current_task {
+			if task.borrow().id == id {
+				task.borrow_mut().prio = prio;
+			}
+		}
+	}
+}
+
+/// Registry of every task that exists, by id, for `join`/`task_exit`
+static mut TASKS: Option<BTreeMap<TaskId, Rc<RefCell<Task>>>> = None;
+
+fn tasks() -> &'static mut BTreeMap<TaskId, Rc<RefCell<Task>>> {
+	unsafe {
+		if TASKS.is_none() {
+			TASKS = Some(BTreeMap::new());
+		}
+
+		TASKS.as_mut().unwrap()
+	}
+}
+
+/// Register a task so `join`/`task_exit` can find it by id. Called once
+/// when the task is created.
+pub fn register_task(task: Rc<RefCell<Task>>) {
+	let id = task.borrow().id;
+	tasks().insert(id, task);
+}
+
+/// Mark `id` as finished with the given exit code and return the ids of
+/// every task that was blocked in `join` on it so they can be re-readied.
+pub fn task_exit(id: TaskId, code: i32) -> LinkedList<TaskId> {
+	match tasks().get(&id) {
+		Some(task) => task.borrow_mut().exit(code),
+		None => Default::default(),
+	}
+}
+
+/// Read the exit code of `id` without blocking and without affecting its
+/// lifetime in the registry.
+///
+/// Returns `None` while the target hasn't finished yet.
+pub fn try_join(id: TaskId) -> Option<i32> {
+	tasks().get(&id)?.borrow().exit_code
+}
+
+/// Register `waiter` to be re-readied once `id` finishes.
+pub fn add_waiter(id: TaskId, waiter: TaskId) {
+	if let Some(task) = tasks().get(&id) {
+		task.borrow_mut().waiters.push_back(waiter);
+	}
+}
+
+/// Record that the caller intends to `join` on `id`, keeping its TCB alive
+/// in the registry until a matching `release_joiner` call.
+fn register_joiner(id: TaskId) {
+	if let Some(task) = tasks().get(&id) {
+		task.borrow_mut().pending_joiners += 1;
+	}
+}
+
+/// Record that one registered joiner has observed `id`'s exit code,
+/// reaping the TCB out of the registry once every joiner that registered
+/// via `register_joiner` has done so.
+fn release_joiner(id: TaskId) {
+	let fully_observed = match tasks().get(&id) {
+		Some(task) => {
+			let mut task = task.borrow_mut();
+			task.pending_joiners = task.pending_joiners.saturating_sub(1);
+			task.pending_joiners == 0
+		}
+		None => false,
+	};
+
+	if fully_observed {
+		tasks().remove(&id);
+	}
+}
+
+/// Hook into the kernel's reschedule routine, set once at boot. `join`
+/// calls this to let another task run while the caller is blocked.
+static mut RESCHEDULE: Option<fn()> = None;
+
+/// Register the function `join` should call to give up the CPU while the
+/// caller is `TaskBlocked`.
+pub fn set_reschedule(f: fn()) {
+	unsafe {
+		RESCHEDULE = Some(f);
+	}
+}
+
+fn reschedule() {
+	unsafe {
+		if let Some(f) = RESCHEDULE {
+			f();
+		}
+	}
+}
+
+/// Block the calling context until `id` finishes and return its exit code.
+///
+/// While the target is still running, the caller registers itself as a
+/// waiter, flips its own status to `TaskBlocked`, and calls the registered
+/// `reschedule` hook so another task can run. `task_exit` does not re-ready
+/// the caller itself: it returns the waiter list, which holds the caller's
+/// id, once the target finishes.
+pub fn join(id: TaskId) -> i32 {
+	register_joiner(id);
+
+	loop {
+		if let Some(code) = try_join(id) {
+			release_joiner(id);
+			return code;
+		}
+
+		if let Some(caller) = current_task() {
+			add_waiter(id, caller.borrow().id);
+			caller.borrow_mut().status = TaskStatus::TaskBlocked;
+		}
+
+		reschedule();
+	}
+}